@@ -70,10 +70,29 @@
 
 use std::{
     num::{NonZeroU64, NonZeroUsize},
+    sync::Arc,
     time::{Duration, Instant},
 };
 
-use dashmap::DashMap;
+use dashmap::{DashMap, DashSet};
+use tokio::task::JoinHandle;
+
+/// which algorithm a [`Limit`] uses to decide when something is limited
+///
+/// see [`Limit::new`] for the classic fixed window and
+/// [`Limit::new_token_bucket`] for continuous refilling
+#[must_use]
+#[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
+pub enum RefillStrategy {
+    /// once [`Limit::count`] usages land within [`Limit::duration`],
+    /// everything is blocked until the whole window elapses, then the usage
+    /// count starts over from the usages registered next
+    FixedWindow,
+    /// the bucket holds up to [`Limit::count`] tokens and refills
+    /// continuously at a rate of `count / duration` tokens per unit time,
+    /// giving smooth throttling instead of hard window edges
+    TokenBucket,
+}
 
 /// information about how often something is able to be used
 ///
@@ -86,6 +105,16 @@ use dashmap::DashMap;
 /// ```
 /// twilight_bucket::Limit::new(std::time::Duration::from_secs(60), 10.try_into()?);
 /// ```
+/// something can be used up to 10 times, smoothly refilling at 10 times per
+/// minute instead of resetting all at once
+/// ```
+/// twilight_bucket::Limit::new_token_bucket(std::time::Duration::from_secs(60), 10.try_into()?);
+/// ```
+/// something can be used every 3 seconds, but a freshly seen ID gets to fire
+/// 5 extra usages up front before that steady limit kicks in
+/// ```
+/// twilight_bucket::Limit::with_burst(std::time::Duration::from_secs(3), 1.try_into()?, 5);
+/// ```
 #[must_use]
 #[derive(Copy, Clone, Eq, PartialEq, Ord, PartialOrd, Hash, Debug)]
 pub struct Limit {
@@ -93,12 +122,57 @@ pub struct Limit {
     duration: Duration,
     /// how many times something can be done in the [`Limit::duration`] period
     count: NonZeroUsize,
+    /// which algorithm is used to decide when something is limited
+    strategy: RefillStrategy,
+    /// how many extra usages a freshly seen ID gets once, on top of
+    /// [`Limit::count`], before the steady limit applies, see
+    /// [`Limit::with_burst`]
+    one_time_burst: usize,
 }
 
 impl Limit {
-    /// create a new [`Limit`]
+    /// create a new [`Limit`] using the classic fixed window, see
+    /// [`RefillStrategy::FixedWindow`]
     pub const fn new(duration: Duration, count: NonZeroUsize) -> Self {
-        Self { duration, count }
+        Self {
+            duration,
+            count,
+            strategy: RefillStrategy::FixedWindow,
+            one_time_burst: 0,
+        }
+    }
+
+    /// create a new [`Limit`] that refills continuously instead of resetting
+    /// all at once, see [`RefillStrategy::TokenBucket`]
+    pub const fn new_token_bucket(duration: Duration, count: NonZeroUsize) -> Self {
+        Self {
+            duration,
+            count,
+            strategy: RefillStrategy::TokenBucket,
+            one_time_burst: 0,
+        }
+    }
+
+    /// create a new [`Limit`] that, in addition to the steady
+    /// `duration`/`count` limit, grants each freshly seen ID `one_time_burst`
+    /// extra usages once, consumed before the steady limit starts counting;
+    /// useful to let onboarding flows or a first flurry of requests through
+    /// without permanently loosening the limit
+    ///
+    /// [`Bucket`] remembers which IDs already drained their burst even after
+    /// their usage is pruned, so the burst stays truly one-time, see
+    /// [`Bucket::prune`]'s documentation
+    pub const fn with_burst(
+        duration: Duration,
+        count: NonZeroUsize,
+        one_time_burst: usize,
+    ) -> Self {
+        Self {
+            duration,
+            count,
+            strategy: RefillStrategy::FixedWindow,
+            one_time_burst,
+        }
     }
 }
 
@@ -106,19 +180,40 @@ impl Limit {
 #[must_use]
 #[derive(Copy, Clone)]
 struct Usage {
-    /// the last time it was used
+    /// the last time it was used, or last checked for token bucket refill
     time: Instant,
-    /// how many times it was used
-    count: NonZeroUsize,
+    /// how many times it was used against the steady window, only meaningful
+    /// for [`RefillStrategy::FixedWindow`]
+    count: usize,
+    /// how many tokens are left, only meaningful for
+    /// [`RefillStrategy::TokenBucket`]
+    tokens: f64,
+    /// how much of [`Limit::one_time_burst`] is left unconsumed
+    burst_remaining: usize,
 }
 
 impl Usage {
-    /// make a usage with now as `time` and 1 as `count`
-    #[allow(clippy::unwrap_used)]
-    fn new() -> Self {
+    /// make a usage with now as `time`, having just consumed `n` usages
+    /// according to `limit`'s strategy, draining `limit`'s one-time burst
+    /// first, unless `burst_already_spent` says this ID drained it before
+    #[allow(clippy::cast_precision_loss)]
+    fn new(limit: &Limit, n: NonZeroUsize, burst_already_spent: bool) -> Self {
+        let burst_available = if burst_already_spent {
+            0
+        } else {
+            limit.one_time_burst
+        };
+        let units_from_burst = n.get().min(burst_available);
+        let units_from_window = n.get() - units_from_burst;
+        let tokens = match limit.strategy {
+            RefillStrategy::FixedWindow => 0.0,
+            RefillStrategy::TokenBucket => limit.count.get() as f64 - units_from_window as f64,
+        };
         Self {
             time: Instant::now(),
-            count: 1.try_into().unwrap(),
+            count: units_from_window,
+            tokens,
+            burst_remaining: burst_available - units_from_burst,
         }
     }
 }
@@ -126,7 +221,10 @@ impl Usage {
 /// this is the main struct to do everything you need
 ///
 /// # thread-safety
-/// you should wrap this in [`Arc`](std::sync::Arc)
+/// usages are stored behind an [`Arc`](std::sync::Arc) internally, so cloning
+/// a [`Bucket`] is cheap and gives you a handle to the same underlying
+/// storage, which is what [`Bucket::new_with_eviction`] relies on to prune in
+/// the background
 ///
 /// # global or task-based
 /// essentially buckets just store usages and limits, meaning you can create a
@@ -140,13 +238,25 @@ impl Usage {
 ///
 /// `ID`s use [`NonZeroU64`](std::num::NonZeroU64) to be compatible with any
 /// kind of ID: users, guilds, even your custom IDs
+///
+/// # per-ID overrides
+/// by default every ID is throttled by the bucket's own [`Limit`], but you
+/// can give specific IDs their own [`Limit`] with
+/// [`Bucket::set_limit_override`], handy for tiered throttling, e.g. premium
+/// users getting a higher limit than everyone else
 #[must_use]
 #[derive(Clone)]
 pub struct Bucket {
-    /// the limit for this bucket
+    /// the limit for this bucket, used for IDs with no override
     limit: Limit,
     /// usage information for IDs
-    usages: DashMap<NonZeroU64, Usage>,
+    usages: Arc<DashMap<NonZeroU64, Usage>>,
+    /// per-ID limits that take precedence over [`Bucket::limit`]
+    limit_overrides: Arc<DashMap<NonZeroU64, Limit>>,
+    /// IDs whose [`Limit::with_burst`] allowance has already been fully
+    /// drained, kept even after their [`Usage`] row is pruned so the burst
+    /// isn't granted to them again
+    spent_bursts: Arc<DashSet<NonZeroU64>>,
 }
 
 impl Bucket {
@@ -154,42 +264,243 @@ impl Bucket {
     pub fn new(limit: Limit) -> Self {
         Self {
             limit,
-            usages: DashMap::new(),
+            usages: Arc::new(DashMap::new()),
+            limit_overrides: Arc::new(DashMap::new()),
+            spent_bursts: Arc::new(DashSet::new()),
         }
     }
 
-    /// register a usage, you should call this every time something you want to
-    /// limit is done **after** waiting for the limit
+    /// give `id` its own [`Limit`], taking precedence over the bucket's
+    /// default limit until [`Bucket::clear_limit_override`] is called
+    pub fn set_limit_override(&self, id: NonZeroU64, limit: Limit) {
+        self.limit_overrides.insert(id, limit);
+    }
+
+    /// remove `id`'s [`Limit`] override, falling back to the bucket's
+    /// default limit again
+    pub fn clear_limit_override(&self, id: NonZeroU64) {
+        self.limit_overrides.remove(&id);
+    }
+
+    /// the limit that applies to `id`: its override if one is set, otherwise
+    /// the bucket's default limit
+    fn limit_for(&self, id: NonZeroU64) -> Limit {
+        self.limit_overrides
+            .get(&id)
+            .map_or(self.limit, |limit| *limit)
+    }
+
+    /// create a new [`Bucket`] that also spawns a background task pruning
+    /// stale usages every `interval`, see [`Bucket::prune`]
     ///
-    /// # Panics
-    /// when the usage count is over `NonZeroUsize`
-    #[allow(clippy::unwrap_used, clippy::integer_arithmetic)]
+    /// useful for long-running bots that see a huge number of unique IDs, so
+    /// memory doesn't grow without bound
+    ///
+    /// with a [`Limit::with_burst`] limit, usages still holding burst
+    /// allowance are never pruned, and IDs that drained their burst are
+    /// remembered for the bucket's whole lifetime, so memory still grows
+    /// with the number of such IDs
+    ///
+    /// also returns the spawned task's [`JoinHandle`], call
+    /// [`JoinHandle::abort`] on it once you're done with the bucket,
+    /// otherwise the task keeps the bucket's storage alive and keeps pruning
+    /// forever, even after every other clone of the returned [`Bucket`] is
+    /// dropped
+    pub fn new_with_eviction(limit: Limit, interval: Duration) -> (Self, JoinHandle<()>) {
+        let bucket = Self::new(limit);
+
+        let pruned_bucket = bucket.clone();
+        let handle = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(interval);
+            loop {
+                interval.tick().await;
+                pruned_bucket.prune();
+            }
+        });
+
+        (bucket, handle)
+    }
+
+    /// remove usages that could no longer be limited, i.e. ones that would
+    /// reset on their next use anyway, returns how many were removed
+    ///
+    /// call this periodically if you have a lot of distinct IDs and don't
+    /// use [`Bucket::new_with_eviction`]
+    ///
+    /// an ID still holding unused [`Limit::with_burst`] allowance is kept
+    /// regardless of staleness, since evicting it would forget how much
+    /// burst it has left; once that allowance is fully drained, [`Bucket`]
+    /// separately remembers that fact, so the usage is pruned like any
+    /// other and the burst still isn't granted again afterwards
+    pub fn prune(&self) -> usize {
+        let before = self.usages.len();
+        self.usages.retain(|&id, usage| {
+            usage.burst_remaining > 0 || Instant::now() < usage.time + self.limit_for(id).duration
+        });
+        before - self.usages.len()
+    }
+
+    /// register a usage, you should call this every time something you want to
+    /// limit is done **after** waiting for the limit, equivalent to
+    /// [`Bucket::register_n`] with `n = 1`
+    #[allow(clippy::unwrap_used)]
     pub fn register(&self, id: NonZeroU64) {
-        match self.usages.get_mut(&id) {
+        self.register_n(id, 1.try_into().unwrap());
+    }
+
+    /// register `n` usages at once, you should call this every time
+    /// something you want to limit is done **after** waiting for the limit,
+    /// use this instead of calling [`Bucket::register`] multiple times when
+    /// a single operation should be charged as more than one usage, e.g. an
+    /// expensive command counting as 5
+    ///
+    /// if `limit` has a [`Limit::with_burst`] allowance left for `id`, that's
+    /// drained first and doesn't count against the steady window
+    #[allow(clippy::integer_arithmetic, clippy::cast_precision_loss)]
+    pub fn register_n(&self, id: NonZeroU64, n: NonZeroUsize) {
+        let limit = self.limit_for(id);
+        let burst_remaining = match self.usages.get_mut(&id) {
             Some(mut usage) => {
+                let units_from_burst = n.get().min(usage.burst_remaining);
+                usage.burst_remaining -= units_from_burst;
+                let units_from_window = n.get() - units_from_burst;
+
                 let now = Instant::now();
-                usage.count = if now - usage.time > self.limit.duration {
-                    1.try_into().unwrap()
-                } else {
-                    (usage.count.get() + 1).try_into().unwrap()
-                };
+                match limit.strategy {
+                    RefillStrategy::FixedWindow => {
+                        usage.count = if now - usage.time > limit.duration {
+                            units_from_window
+                        } else {
+                            usage.count + units_from_window
+                        };
+                    }
+                    RefillStrategy::TokenBucket => {
+                        usage.tokens =
+                            self.tokens_after_refill(&usage, &limit) - units_from_window as f64;
+                    }
+                }
                 usage.time = now;
+                usage.burst_remaining
             }
             None => {
-                self.usages.insert(id, Usage::new());
+                let usage = Usage::new(&limit, n, self.spent_bursts.contains(&id));
+                let burst_remaining = usage.burst_remaining;
+                self.usages.insert(id, usage);
+                burst_remaining
             }
+        };
+
+        if burst_remaining == 0 && limit.one_time_burst > 0 {
+            self.spent_bursts.insert(id);
         }
     }
 
     /// get the duration to wait until the next usage by `id`, returns `None`
     /// if the ID isn't limited, you should call this **before** registering a
-    /// usage
+    /// usage, equivalent to [`Bucket::limit_duration_n`] with `n = 1`
     #[must_use]
+    #[allow(clippy::unwrap_used)]
     pub fn limit_duration(&self, id: NonZeroU64) -> Option<Duration> {
+        self.limit_duration_n(id, 1.try_into().unwrap())
+    }
+
+    /// get the duration to wait until `n` more usages by `id` are free,
+    /// returns `None` if the ID isn't limited for `n` usages, you should
+    /// call this **before** registering a usage with [`Bucket::register_n`]
+    ///
+    /// any [`Limit::with_burst`] allowance `id` still has is subtracted from
+    /// `n` first, only the remainder (if any) is checked against the steady
+    /// limit, matching how [`Bucket::register_n`] charges it
+    #[must_use]
+    #[allow(clippy::cast_precision_loss)]
+    pub fn limit_duration_n(&self, id: NonZeroU64, n: NonZeroUsize) -> Option<Duration> {
         let usage = self.usages.get(&id)?;
+        let units_from_window = n.get().saturating_sub(usage.burst_remaining);
+        let units_from_window = match NonZeroUsize::new(units_from_window) {
+            Some(units_from_window) => units_from_window,
+            None => return None,
+        };
+
+        let limit = self.limit_for(id);
+        match limit.strategy {
+            RefillStrategy::FixedWindow => {
+                let elapsed = Instant::now() - usage.time;
+                (usage.count + units_from_window.get() > limit.count.get()
+                    && limit.duration > elapsed)
+                    .then(|| limit.duration - elapsed)
+            }
+            RefillStrategy::TokenBucket => {
+                let tokens = self.tokens_after_refill(&usage, &limit);
+                let units_from_window = units_from_window.get() as f64;
+                (tokens < units_from_window).then(|| {
+                    Duration::from_secs_f64((units_from_window - tokens) / self.refill_rate(&limit))
+                })
+            }
+        }
+    }
+
+    /// wait until a usage slot for `id` is free, then register it
+    ///
+    /// this combines [`Bucket::limit_duration`] and [`Bucket::register`] into
+    /// one call without the race that comes with calling them separately:
+    /// another task could register a usage while you're sleeping, so the
+    /// limit is re-checked after every sleep
+    pub async fn acquire(&self, id: NonZeroU64) {
+        while let Some(duration) = self.limit_duration(id) {
+            tokio::time::sleep(duration).await;
+        }
+        self.register(id);
+    }
+
+    /// register a usage for `id` if a slot is free right now, otherwise
+    /// return `Err` with the duration that would need to be waited, without
+    /// registering
+    #[must_use]
+    pub fn try_acquire(&self, id: NonZeroU64) -> Result<(), Duration> {
+        match self.limit_duration(id) {
+            Some(duration) => Err(duration),
+            None => {
+                self.register(id);
+                Ok(())
+            }
+        }
+    }
+
+    /// like [`Bucket::acquire`], but returns `Err` with the required wait
+    /// instead of sleeping if it would exceed `max_wait`
+    #[must_use]
+    pub async fn acquire_timeout(
+        &self,
+        id: NonZeroU64,
+        max_wait: Duration,
+    ) -> Result<(), Duration> {
+        loop {
+            match self.limit_duration(id) {
+                Some(duration) if duration > max_wait => return Err(duration),
+                Some(duration) => tokio::time::sleep(duration).await,
+                None => {
+                    self.register(id);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// how many tokens per second `limit` refills, only meaningful for
+    /// [`RefillStrategy::TokenBucket`]
+    #[allow(clippy::cast_precision_loss)]
+    fn refill_rate(&self, limit: &Limit) -> f64 {
+        limit.count.get() as f64 / limit.duration.as_secs_f64()
+    }
+
+    /// the token count `usage` would have if it refilled up to now under
+    /// `limit`, clamped to `limit`'s capacity, only meaningful for
+    /// [`RefillStrategy::TokenBucket`]
+    #[allow(clippy::cast_precision_loss)]
+    fn tokens_after_refill(&self, usage: &Usage, limit: &Limit) -> f64 {
         let elapsed = Instant::now() - usage.time;
-        (usage.count >= self.limit.count && self.limit.duration > elapsed)
-            .then(|| self.limit.duration - elapsed)
+        let capacity = limit.count.get() as f64;
+        (usage.tokens + elapsed.as_secs_f64() * self.refill_rate(limit)).min(capacity)
     }
 }
 
@@ -236,4 +547,175 @@ mod tests {
         sleep(bucket.limit.duration).await;
         assert!(bucket.limit_duration(id).is_none());
     }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn token_bucket_refills_continuously() {
+        let bucket = Bucket::new(Limit::new_token_bucket(
+            Duration::from_secs(4),
+            4.try_into().unwrap(),
+        ));
+        let id = 123.try_into().unwrap();
+
+        for _ in 0_u8..4 {
+            assert!(bucket.limit_duration(id).is_none());
+            bucket.register(id);
+        }
+        assert!(bucket.limit_duration(id).is_some());
+
+        sleep(Duration::from_secs(1)).await;
+        assert!(bucket.limit_duration(id).is_none());
+        bucket.register(id);
+        assert!(bucket.limit_duration(id).is_some());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn prune_removes_stale_usages() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(1), 1.try_into().unwrap()));
+        let id = 123.try_into().unwrap();
+
+        bucket.register(id);
+        assert_eq!(bucket.prune(), 0);
+
+        sleep(Duration::from_secs(1)).await;
+        assert_eq!(bucket.prune(), 1);
+        assert_eq!(bucket.prune(), 0);
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn acquire_waits_for_a_free_slot() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(1), 1.try_into().unwrap()));
+        let id = 123.try_into().unwrap();
+
+        bucket.acquire(id).await;
+        assert!(bucket.limit_duration(id).is_some());
+
+        let start = tokio::time::Instant::now();
+        bucket.acquire(id).await;
+        assert!(start.elapsed() >= Duration::from_secs(1) - Duration::from_secs_f32(0.1));
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn try_acquire_does_not_register_when_limited() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(1), 1.try_into().unwrap()));
+        let id = 123.try_into().unwrap();
+
+        assert!(bucket.try_acquire(id).is_ok());
+        assert!(bucket.try_acquire(id).is_err());
+        assert_eq!(bucket.usages.get(&id).unwrap().count, 1);
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn acquire_timeout_errors_when_wait_is_too_long() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(1), 1.try_into().unwrap()));
+        let id = 123.try_into().unwrap();
+
+        bucket.register(id);
+        assert!(bucket
+            .acquire_timeout(id, Duration::from_millis(1))
+            .await
+            .is_err());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn register_n_charges_multiple_units() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(2), 5.try_into().unwrap()));
+        let id = 123.try_into().unwrap();
+
+        assert!(bucket.limit_duration_n(id, 5.try_into().unwrap()).is_none());
+        bucket.register_n(id, 3.try_into().unwrap());
+
+        assert!(bucket.limit_duration_n(id, 2.try_into().unwrap()).is_none());
+        assert!(bucket.limit_duration_n(id, 3.try_into().unwrap()).is_some());
+
+        sleep(bucket.limit.duration).await;
+        assert!(bucket.limit_duration_n(id, 5.try_into().unwrap()).is_none());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn limit_override_takes_precedence() {
+        let bucket = Bucket::new(Limit::new(Duration::from_secs(60), 1.try_into().unwrap()));
+        let premium_id = 123.try_into().unwrap();
+        let free_id = 456.try_into().unwrap();
+
+        bucket.set_limit_override(
+            premium_id,
+            Limit::new(Duration::from_secs(60), 20.try_into().unwrap()),
+        );
+
+        bucket.register(premium_id);
+        assert!(bucket.limit_duration(premium_id).is_none());
+
+        bucket.register(free_id);
+        assert!(bucket.limit_duration(free_id).is_some());
+
+        bucket.clear_limit_override(premium_id);
+        bucket.register(premium_id);
+        assert!(bucket.limit_duration(premium_id).is_some());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn one_time_burst_is_consumed_before_the_steady_limit() {
+        let bucket = Bucket::new(Limit::with_burst(
+            Duration::from_secs(60),
+            1.try_into().unwrap(),
+            2,
+        ));
+        let id = 123.try_into().unwrap();
+
+        bucket.register(id);
+        assert!(bucket.limit_duration(id).is_none());
+        bucket.register(id);
+        assert!(bucket.limit_duration(id).is_none());
+
+        bucket.register(id);
+        assert!(bucket.limit_duration(id).is_some());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[test]
+    fn limit_duration_n_checks_the_steady_limit_for_the_part_burst_does_not_cover() {
+        let bucket = Bucket::new(Limit::with_burst(
+            Duration::from_secs(60),
+            1.try_into().unwrap(),
+            2,
+        ));
+        let id = 123.try_into().unwrap();
+
+        bucket.register(id);
+        assert!(bucket.limit_duration_n(id, 5.try_into().unwrap()).is_some());
+        assert!(bucket.limit_duration_n(id, 1.try_into().unwrap()).is_none());
+    }
+
+    #[allow(clippy::unwrap_used)]
+    #[tokio::test]
+    async fn prune_does_not_re_grant_a_consumed_burst() {
+        let bucket = Bucket::new(Limit::with_burst(
+            Duration::from_millis(200),
+            1.try_into().unwrap(),
+            3,
+        ));
+        let id = 123.try_into().unwrap();
+
+        for _ in 0_u8..3 {
+            bucket.register(id);
+        }
+
+        // the burst is fully drained and the window is stale, so the usage
+        // row itself is now prunable
+        sleep(Duration::from_millis(250)).await;
+        assert_eq!(bucket.prune(), 1);
+
+        // only the steady limit applies to this registration; if the burst
+        // had come back, it would absorb it and `id` wouldn't be limited
+        bucket.register(id);
+        assert!(bucket.limit_duration(id).is_some());
+    }
 }